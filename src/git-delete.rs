@@ -1,8 +1,16 @@
 mod common;
 
+use common::Git;
+
 use anyhow::{bail, Context, Result};
+use git2::{Branch, BranchType, Oid, Repository, StatusOptions, Worktree, WorktreePruneOptions};
+use globset::Glob;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
-use std::io::Write;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -11,7 +19,57 @@ use structopt::StructOpt;
     about = env!("CARGO_PKG_DESCRIPTION")
 )]
 pub struct Delete {
-    branch_name: String,
+    /// Delete the branch even if it has not been merged into the default branch.
+    #[structopt(long, short = "f")]
+    force: bool,
+
+    /// Delete all the local branches that have been merged into the default branch.
+    #[structopt(long)]
+    merged: bool,
+
+    /// Delete all the local branches whose upstream branch has been deleted from the remote.
+    #[structopt(long)]
+    gone: bool,
+
+    /// Do not ask for confirmation before deleting the branches found by --merged or --gone.
+    #[structopt(long, short = "y")]
+    yes: bool,
+
+    /// List the deleted branches that can be restored.
+    #[structopt(long, conflicts_with_all = &["merged", "gone", "restore"])]
+    list_deleted: bool,
+
+    /// Restore a branch previously deleted.
+    #[structopt(long, value_name = "branch", conflicts_with_all = &["merged", "gone"])]
+    restore: Option<String>,
+
+    /// Push the restored branch to its former upstream.
+    #[structopt(long, requires = "restore")]
+    push: bool,
+
+    /// Show the branches that would be deleted and why without deleting anything.
+    #[structopt(long, conflicts_with_all = &["list-deleted", "restore"])]
+    dry_run: bool,
+
+    /// Delete remote branches (e.g. origin/stale-branch) instead of local branches.
+    #[structopt(long, short = "r", requires = "branch-names")]
+    remote: bool,
+
+    /// Allow deleting the current branch by switching to the default branch first. This is
+    /// the default when no branch name is given.
+    #[structopt(long, short = "s")]
+    switch: bool,
+
+    /// Also delete the upstream branch when it has another name than the local branch (e.g.
+    /// foo tracking origin/feature/foo-v2). By default such an upstream is kept as it might be
+    /// the base branch the local branch was created from.
+    #[structopt(long, short = "u", conflicts_with = "remote")]
+    upstream: bool,
+
+    /// Names or glob patterns (e.g. 'experiment/*') of the branches to delete (the current
+    /// branch by default).
+    #[structopt(conflicts_with_all = &["merged", "gone", "list-deleted", "restore"])]
+    branch_names: Vec<String>,
 }
 
 fn main() {
@@ -26,7 +84,7 @@ fn execute() -> i32 {
     let opts = Delete::from_args();
 
     if let Err(err) = run(opts) {
-        eprintln!("{}", err);
+        eprintln!("{:#}", err);
 
         FAILURE
     } else {
@@ -35,73 +93,1212 @@ fn execute() -> i32 {
 }
 
 pub fn run(params: Delete) -> Result<()> {
-    let branch_name = params.branch_name.as_str();
-    let repo = git2::Repository::open(".").context("Could not open repository")?;
+    let repo = Repository::open(".").context("Could not open repository")?;
 
-    let mut branch = repo
-        .find_branch(&params.branch_name, git2::BranchType::Local)
-        .with_context(|| format!("Could not find local branch: {}", params.branch_name))?;
-    let branch_name = branch
-        .name()
-        .context("Could not retrieve branch name")?
-        .expect("not valid utf-8")
-        .to_owned();
+    if params.merged || params.gone {
+        return delete_stale_branches(&repo, &params);
+    }
 
-    if branch.is_head() {
-        bail!("Aborted: cannot delete branch currently pointed at by HEAD");
-    }
-
-    // delete remote branch if any
-    if let Ok(upstream) = branch.upstream() {
-        let upstream_name = upstream.get().name().expect("not valid utf-8");
-        let remote_name = upstream_name
-            .strip_suffix(&branch_name)
-            .and_then(|x| x.strip_prefix("refs/remotes/"))
-            .context("Could not find remote name")?
-            .trim_end_matches('/');
-
-        let mut remote = repo
-            .find_remote(remote_name)
-            .with_context(|| format!("Could not find remote `{}`", remote_name))?;
-
-        // this is a reference to the default branch if it exists
-        let head_reference = repo.find_reference(&format!("refs/remotes/{}/HEAD", remote_name));
-
-        let default_branch_name = {
-            match head_reference.as_ref() {
-                Ok(reference) => reference
-                    .symbolic_target()
-                    .context("Invalid reference HEAD: not symbolic reference")?
-                    .to_string(),
-                Err(err) if err.code() == git2::ErrorCode::NotFound => {
-                    format!("refs/remote/{}/master", remote_name)
+    if params.list_deleted {
+        return list_deleted_branches(&repo);
+    }
+
+    if let Some(branch_name) = params.restore.as_deref() {
+        return restore_branch(&repo, branch_name, params.push);
+    }
+
+    let current_branch = repo
+        .head()
+        .ok()
+        .filter(|x| x.is_branch())
+        .and_then(|x| x.shorthand().map(|x| x.to_string()));
+    let switch = params.switch || params.branch_names.is_empty();
+    let patterns = if params.branch_names.is_empty() {
+        vec![current_branch
+            .clone()
+            .context("Cannot delete the current branch: HEAD is detached")?]
+    } else {
+        params.branch_names.clone()
+    };
+
+    let mut failures = Vec::new();
+    let mut selected = Vec::new();
+    let branch_type = if params.remote {
+        BranchType::Remote
+    } else {
+        BranchType::Local
+    };
+    let mut branch_names = resolve_branch_names(&repo, &patterns, branch_type, &mut failures)?;
+
+    // NOTE: the current branch can be deleted only after switching to the default branch which
+    //       needs to be fetched first to know if the branch has been merged
+    let mut switch_to = None;
+    if let Some(current_branch) = current_branch
+        .filter(|x| switch && branch_type == BranchType::Local && branch_names.contains(x))
+    {
+        let git = Git::open()?;
+        if git.has_file_changes()? {
+            bail!("The repository has not committed changes, aborting.");
+        }
+
+        let remote_name = repo
+            .branch_upstream_remote(&format!("refs/heads/{}", current_branch))
+            .ok()
+            .and_then(|x| x.as_str().map(|x| x.to_string()))
+            .unwrap_or_else(|| "origin".to_string());
+        let default_branch = common::get_default_branch(&repo, &remote_name)
+            .context("Could not find default branch for this repository")?;
+        git.update_upstream(&default_branch)
+            .with_context(|| format!("Could not fetch {}", default_branch))?;
+
+        if default_branch.strip_prefix(&format!("{}/", remote_name))
+            != Some(current_branch.as_str())
+        {
+            switch_to = Some((git, current_branch, default_branch));
+        } else {
+            branch_names.retain(|x| *x != current_branch);
+            failures.push((
+                current_branch,
+                "cannot delete the default branch".to_string(),
+            ));
+        }
+    }
+
+    for branch_name in branch_names {
+        match check_branch(
+            &repo,
+            &branch_name,
+            branch_type,
+            params.force,
+            switch_to.is_some(),
+        ) {
+            Ok(reason) => selected.push((branch_name, reason)),
+            Err(err) => failures.push((branch_name, err.to_string())),
+        }
+    }
+
+    if let Some((mut git, current_branch, default_branch)) = switch_to {
+        if selected.iter().any(|(x, _)| *x == current_branch) {
+            if params.dry_run {
+                println!(
+                    "Would switch to {} before deleting {}.",
+                    default_branch, current_branch
+                );
+            } else {
+                switch_to_default_branch(&mut git, &default_branch)?;
+            }
+        }
+    }
+
+    delete_branches(
+        &repo,
+        selected,
+        branch_type,
+        &params,
+        &HashSet::new(),
+        failures,
+    )
+}
+
+/// Switch to the local copy of the default branch, creating it or fast-forwarding it to the
+/// remote branch if needed.
+fn switch_to_default_branch(git: &mut Git, default_branch: &str) -> Result<()> {
+    let (_, branch_name) = default_branch
+        .split_once('/')
+        .context("Invalid default branch")?;
+    let remote_oid = git
+        .repo
+        .refname_to_id(&format!("refs/remotes/{}", default_branch))?;
+
+    match git.repo.find_branch(branch_name, BranchType::Local) {
+        Ok(mut branch) => {
+            let local_oid = branch
+                .get()
+                .target()
+                .context("Branch is not a direct reference")?;
+            if local_oid != remote_oid && git.repo.graph_descendant_of(remote_oid, local_oid)? {
+                branch.get_mut().set_target(
+                    remote_oid,
+                    &format!("git delete: fast-forward to {}", default_branch),
+                )?;
+                println!(
+                    "Branch {} fast-forwarded to {}.",
+                    branch_name, default_branch
+                );
+            }
+        }
+        Err(_) => {
+            git.branch(branch_name, Some(default_branch), false)?;
+            git.set_upstream(branch_name, default_branch)?;
+            println!("Branch {} created from {}.", branch_name, default_branch);
+        }
+    }
+
+    git.switch_branch(branch_name)?;
+    println!("Switched to branch {}.", branch_name);
+
+    Ok(())
+}
+
+/// Find the branches matching the names and glob patterns given. The names that don't match
+/// any branch are added to the failures.
+fn resolve_branch_names(
+    repo: &Repository,
+    patterns: &[String],
+    branch_type: BranchType,
+    failures: &mut Vec<(String, String)>,
+) -> Result<Vec<String>> {
+    let mut all_branch_names = Vec::new();
+    for branch in repo.branches(Some(branch_type))? {
+        let (branch, _) = branch?;
+        // NOTE: skip the symbolic references like origin/HEAD
+        if branch.get().symbolic_target().is_some() {
+            continue;
+        }
+        all_branch_names.push(
+            branch
+                .name()
+                .context("Could not retrieve branch name")?
+                .expect("not valid utf-8")
+                .to_owned(),
+        );
+    }
+
+    let mut branch_names = Vec::new();
+    for pattern in patterns {
+        let matches: Vec<&String> = if pattern.contains(['*', '?', '[', '{']) {
+            let glob = Glob::new(pattern)
+                .with_context(|| format!("Invalid pattern: {}", pattern))?
+                .compile_matcher();
+            all_branch_names
+                .iter()
+                .filter(|x| glob.is_match(x.as_str()))
+                .collect()
+        } else {
+            all_branch_names.iter().filter(|x| *x == pattern).collect()
+        };
+
+        if matches.is_empty() {
+            failures.push((
+                pattern.clone(),
+                match branch_type {
+                    BranchType::Local => "could not find local branch".to_string(),
+                    BranchType::Remote => "could not find remote branch".to_string(),
+                },
+            ));
+        }
+
+        for branch_name in matches {
+            if !branch_names.contains(branch_name) {
+                branch_names.push(branch_name.clone());
+            }
+        }
+    }
+
+    Ok(branch_names)
+}
+
+/// Make sure the branch can be deleted. Returns the reason why it can be deleted.
+fn check_branch(
+    repo: &Repository,
+    branch_name: &str,
+    branch_type: BranchType,
+    force: bool,
+    allow_head: bool,
+) -> Result<String> {
+    let branch = repo.find_branch(branch_name, branch_type)?;
+
+    if branch.is_head() && !allow_head {
+        bail!("cannot delete branch currently pointed at by HEAD (use --switch to switch to the default branch first)");
+    }
+
+    match branch_type {
+        BranchType::Local => check_protected(repo, branch_name)?,
+        BranchType::Remote => check_remote_branch(
+            repo,
+            &RemoteBranch::from_tracking_ref(repo, branch.get().name().expect("not valid utf-8"))?,
+        )?,
+    }
+
+    if force {
+        Ok("forced".to_string())
+    } else {
+        let default_branch = check_merged(repo, &branch)?;
+        Ok(format!("merged into {}", default_branch))
+    }
+}
+
+/// Delete the local branches and their upstream branches if any (or only the remote branches).
+/// The remote branches are deleted with one push per remote and the local branches (and their
+/// worktrees) are deleted only if their upstream branch has been deleted successfully. A summary of the failures is
+/// printed at the end.
+fn delete_branches(
+    repo: &Repository,
+    branches: Vec<(String, String)>,
+    branch_type: BranchType,
+    params: &Delete,
+    gone_upstreams: &HashSet<String>,
+    mut failures: Vec<(String, String)>,
+) -> Result<()> {
+    let mut remote_branches: BTreeMap<String, Vec<(String, RemoteBranch, Vec<Worktree>)>> =
+        BTreeMap::new();
+    let mut deletions = Vec::new();
+    for (branch_name, reason) in branches {
+        let result = match branch_type {
+            BranchType::Local => get_worktrees(repo, &branch_name).and_then(|worktrees| {
+                Ok((
+                    worktrees,
+                    get_upstream_to_delete(repo, &branch_name, params, gone_upstreams)?,
+                ))
+            }),
+            BranchType::Remote => {
+                RemoteBranch::from_tracking_ref(repo, &format!("refs/remotes/{}", branch_name))
+                    .map(|x| (Vec::new(), Upstream::Delete(x)))
+            }
+        };
+
+        match result {
+            Ok((worktrees, upstream)) if params.dry_run => {
+                println!("{} ({}):", branch_name, reason);
+                for worktree in worktrees {
+                    println!("  would remove worktree {}", worktree.path().display());
+                }
+                if branch_type == BranchType::Local {
+                    println!("  would delete local branch refs/heads/{}", branch_name);
+                }
+                match upstream {
+                    Upstream::Delete(remote_branch) => println!(
+                        "  would delete {} on {} ({})",
+                        remote_branch.remote_ref,
+                        remote_branch.remote_name,
+                        remote_branch.tracking_ref
+                    ),
+                    Upstream::Keep(tracking_ref, note) => {
+                        println!("  would keep upstream {} ({})", tracking_ref, note)
+                    }
+                    Upstream::Gone(tracking_ref) => {
+                        println!("  would prune {} (deleted from the remote)", tracking_ref)
+                    }
+                    Upstream::None => {}
                 }
-                Err(err) => bail!("Could not find default branch for this repository: {}", err),
+            }
+            Ok((worktrees, upstream)) => {
+                let remote_branch = match upstream {
+                    Upstream::Delete(remote_branch) => Some(remote_branch),
+                    Upstream::Keep(tracking_ref, note) => {
+                        println!(
+                            "Upstream of {} kept: {} ({})",
+                            branch_name, tracking_ref, note
+                        );
+                        None
+                    }
+                    Upstream::Gone(_) | Upstream::None => None,
+                };
+
+                match remote_branch {
+                    Some(remote_branch) => remote_branches
+                        .entry(remote_branch.remote_name.clone())
+                        .or_default()
+                        .push((branch_name, remote_branch, worktrees)),
+                    None => deletions.push((branch_name, None, worktrees)),
+                }
+            }
+            Err(err) => failures.push((branch_name, err.to_string())),
+        }
+    }
+
+    for (remote_name, branches) in remote_branches {
+        // NOTE: the remote branches are deleted only if they have not been updated since the last
+        //       fetch, otherwise the new commits would be lost
+        let remote_refs = match common::list_remote_refs(repo, &remote_name, git2::Direction::Push)
+        {
+            Ok(remote_refs) => remote_refs,
+            Err(err) => {
+                for (branch_name, _, _) in branches {
+                    failures.push((branch_name, err.to_string()));
+                }
+                continue;
             }
         };
 
-        if upstream_name == default_branch_name {
-            bail!("Aborted: deleting default branch is forbidden");
+        let mut pushed = Vec::new();
+        for (branch_name, remote_branch, worktrees) in branches {
+            match remote_refs
+                .iter()
+                .find(|(refname, _)| *refname == remote_branch.remote_ref)
+            {
+                Some((_, oid)) if *oid != remote_branch.oid => failures.push((
+                    branch_name,
+                    format!(
+                        "stale info: {} has been updated on the remote since the last fetch \
+                        (fetch and check it again)",
+                        remote_branch.tracking_ref
+                    ),
+                )),
+                Some(_) => pushed.push((branch_name, remote_branch, worktrees)),
+                // NOTE: the branch has already been deleted from the remote
+                None => match repo
+                    .find_reference(&remote_branch.tracking_ref)
+                    .and_then(|mut x| x.delete())
+                {
+                    Ok(()) => deletions.push((branch_name, Some(remote_branch), worktrees)),
+                    Err(err) => failures.push((branch_name, err.to_string())),
+                },
+            }
+        }
+
+        if pushed.is_empty() {
+            continue;
+        }
+
+        let refspecs: Vec<String> = pushed
+            .iter()
+            .map(|(_, remote_branch, _)| format!("+:{}", remote_branch.remote_ref))
+            .collect();
+        let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+        let leases: Vec<(&str, Oid)> = pushed
+            .iter()
+            .map(|(_, remote_branch, _)| (remote_branch.remote_ref.as_str(), remote_branch.oid))
+            .collect();
+
+        match common::push_with_lease(repo, &remote_name, &refspecs, &leases) {
+            Ok(updates) => {
+                for (branch_name, remote_branch, worktrees) in pushed {
+                    match updates
+                        .iter()
+                        .find(|(refname, _)| *refname == remote_branch.remote_ref)
+                    {
+                        Some((_, Some(message))) => failures.push((
+                            branch_name,
+                            format!("the remote rejected the deletion: {}", message),
+                        )),
+                        _ => deletions.push((branch_name, Some(remote_branch), worktrees)),
+                    }
+                }
+            }
+            Err(err) => {
+                for (branch_name, _, _) in pushed {
+                    failures.push((branch_name, err.to_string()));
+                }
+            }
+        }
+    }
+
+    for (branch_name, remote_branch, worktrees) in deletions {
+        if let (BranchType::Remote, Some(remote_branch)) = (branch_type, remote_branch.as_ref()) {
+            println!("Remote branch deleted: {}", branch_name);
+            if let Err(err) = backup_remote_branch(repo, remote_branch) {
+                failures.push((branch_name, err.to_string()));
+            }
+            continue;
+        }
+
+        if let Some(remote_branch) = remote_branch {
+            println!("Upstream deleted: {}", remote_branch.tracking_ref);
+        }
+
+        match remove_worktrees(&branch_name, worktrees, params.yes)
+            .and_then(|()| delete_local_branch(repo, &branch_name))
+        {
+            Ok(()) => println!("Local branch deleted: {}", branch_name),
+            Err(err) => failures.push((branch_name, err.to_string())),
+        }
+    }
+
+    if !params.dry_run {
+        prune_deleted_branches(repo).context("Could not prune the expired backups")?;
+    }
+
+    for (branch_name, message) in failures.iter() {
+        eprintln!("Could not delete {}: {}", branch_name, message);
+    }
+
+    if !failures.is_empty() {
+        bail!("{} branch(es) could not be deleted.", failures.len());
+    }
+
+    Ok(())
+}
+
+/// A branch on a remote.
+struct RemoteBranch {
+    remote_name: String,
+    /// Reference of the branch on the remote (e.g. refs/heads/main).
+    remote_ref: String,
+    /// Remote-tracking reference of the branch (e.g. refs/remotes/origin/main).
+    tracking_ref: String,
+    /// Commit of the remote-tracking reference.
+    oid: Oid,
+}
+
+impl RemoteBranch {
+    /// Find the remote and the reference on the remote of a remote-tracking reference using the
+    /// fetch refspecs of the remote.
+    fn from_tracking_ref(repo: &Repository, tracking_ref: &str) -> Result<RemoteBranch> {
+        let remote_name = repo
+            .branch_remote_name(tracking_ref)
+            .ok()
+            .and_then(|x| x.as_str().map(|x| x.to_string()))
+            .with_context(|| format!("Could not find the remote of {}", tracking_ref))?;
+        let remote = repo.find_remote(&remote_name)?;
+        let remote_ref = remote
+            .refspecs()
+            .find(|x| x.direction() == git2::Direction::Fetch && x.dst_matches(tracking_ref))
+            .and_then(|x| x.rtransform(tracking_ref).ok())
+            .and_then(|x| x.as_str().map(|x| x.to_string()))
+            .with_context(|| format!("Could not find the remote branch of {}", tracking_ref))?;
+        let oid = repo.refname_to_id(tracking_ref)?;
+
+        Ok(RemoteBranch {
+            remote_name,
+            remote_ref,
+            tracking_ref: tracking_ref.to_string(),
+            oid,
+        })
+    }
+
+    /// Name of the branch on the remote (e.g. main).
+    fn branch_name(&self) -> &str {
+        self.remote_ref.trim_start_matches("refs/heads/")
+    }
+}
+
+/// Make sure the remote branch is neither the default branch nor protected.
+fn check_remote_branch(repo: &Repository, remote_branch: &RemoteBranch) -> Result<()> {
+    let default_branch_name = format!(
+        "refs/remotes/{}",
+        common::get_default_branch(repo, &remote_branch.remote_name)
+            .context("Could not find default branch for this repository")?
+    );
+
+    if remote_branch.tracking_ref == default_branch_name {
+        bail!("deleting default branch is forbidden");
+    }
+
+    check_protected(repo, remote_branch.branch_name())
+}
+
+/// What to do with the upstream branch of a local branch being deleted.
+enum Upstream {
+    None,
+    Delete(RemoteBranch),
+    /// The upstream is kept (remote-tracking reference and why).
+    Keep(String, String),
+    /// The upstream has been deleted from the remote but it has not been pruned (dry-run).
+    Gone(String),
+}
+
+/// Returns the upstream branch that needs to be deleted along with the local branch if any.
+/// The branch on the remote is the one configured in branch.<name>.merge. If it has another
+/// name than the local branch, it is most likely the base branch (e.g. git fork --track base)
+/// so it is kept unless --upstream is used. The upstream must be merged too unless --force is
+/// used as it might have commits that have never been fetched in the local branch.
+fn get_upstream_to_delete(
+    repo: &Repository,
+    branch_name: &str,
+    params: &Delete,
+    gone_upstreams: &HashSet<String>,
+) -> Result<Upstream> {
+    let branch = repo.find_branch(branch_name, BranchType::Local)?;
+    let upstream = match branch.upstream() {
+        Ok(upstream) => upstream,
+        Err(_) => return Ok(Upstream::None),
+    };
+    let tracking_ref = upstream.get().name().expect("not valid utf-8").to_string();
+
+    if gone_upstreams.contains(&tracking_ref) {
+        return Ok(Upstream::Gone(tracking_ref));
+    }
+
+    let remote_name =
+        get_upstream_remote_name(repo, &branch).context("Could not find remote name")?;
+    let remote_ref = repo
+        .config()?
+        .get_string(&format!("branch.{}.merge", branch_name))
+        .context("Could not find the remote branch")?;
+    let remote_branch = RemoteBranch {
+        remote_name,
+        remote_ref,
+        tracking_ref,
+        oid: upstream
+            .get()
+            .target()
+            .context("Upstream is not a direct reference")?,
+    };
+
+    if remote_branch.remote_ref == format!("refs/heads/{}", branch_name) {
+        check_remote_branch(repo, &remote_branch)?;
+    } else if !params.upstream {
+        return Ok(Upstream::Keep(
+            remote_branch.tracking_ref,
+            "not the remote branch of the same name, use --upstream to delete it".to_string(),
+        ));
+    } else if let Err(err) = check_remote_branch(repo, &remote_branch) {
+        return Ok(Upstream::Keep(remote_branch.tracking_ref, err.to_string()));
+    }
+
+    if !params.force && branch.get().target() != Some(remote_branch.oid) {
+        check_merged(repo, &upstream)?;
+    }
+
+    Ok(Upstream::Delete(remote_branch))
+}
+
+fn delete_local_branch(repo: &Repository, branch_name: &str) -> Result<()> {
+    let branch = repo.find_branch(branch_name, BranchType::Local)?;
+    if branch.is_head() {
+        bail!("cannot delete branch currently pointed at by HEAD");
+    }
+    let oid = branch
+        .get()
+        .target()
+        .context("Branch is not a direct reference")?;
+    let upstream = repo
+        .branch_upstream_name(&format!("refs/heads/{}", branch_name))
+        .ok()
+        .and_then(|x| x.as_str().map(|x| x.to_string()));
+
+    // NOTE: the reference is deleted directly as Branch::delete() also removes the configuration
+    //       of the branches whose names start with "<name>." (e.g. v1.2 when deleting v1)
+    branch.into_reference().delete()?;
+    backup_branch(repo, branch_name, oid, upstream.as_deref()).with_context(|| {
+        format!(
+            "branch deleted but could not be backed up (its tip was {})",
+            oid
+        )
+    })?;
+    remove_branch_config(repo, branch_name)?;
+
+    Ok(())
+}
+
+/// Remove all the configuration of the branch (branch.<name>.*), including the entries added
+/// by git-tools like branch.<name>.git-tools-parent.
+fn remove_branch_config(repo: &Repository, branch_name: &str) -> Result<()> {
+    let mut config = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+
+    let mut names = Vec::new();
+    {
+        let mut entries = config.entries(None)?;
+        while let Some(entry) = entries.next().transpose()? {
+            let name = entry.name().expect("invalid UTF-8");
+            if is_branch_config(name, branch_name) && !names.iter().any(|x| x == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    for name in names {
+        config.remove_multivar(&name, ".*")?;
+    }
+
+    Ok(())
+}
+
+/// Check if the configuration entry belongs to the branch. The branch name may contain dots
+/// but the variable name can't (e.g. branch.v1.2.merge belongs to v1.2, not v1).
+fn is_branch_config(name: &str, branch_name: &str) -> bool {
+    name.strip_prefix("branch.")
+        .and_then(|x| x.strip_prefix(branch_name))
+        .and_then(|x| x.strip_prefix('.'))
+        .is_some_and(|key| !key.is_empty() && !key.contains('.'))
+}
+
+/// Linked worktrees in which the branch is checked out. Fails if one of them has uncommitted
+/// changes or if the branch is checked out in the main worktree.
+fn get_worktrees(repo: &Repository, branch_name: &str) -> Result<Vec<Worktree>> {
+    let head = format!("refs/heads/{}", branch_name);
+    let mut worktrees = Vec::new();
+
+    // NOTE: Branch::is_head() only checks the HEAD of the current worktree
+    if repo.is_worktree() {
+        let main_repo = Repository::open(common_dir(repo))?;
+        if main_repo
+            .head()
+            .ok()
+            .and_then(|x| x.name().map(|x| x == head))
+            == Some(true)
+        {
+            bail!(
+                "branch is checked out in the main worktree {}",
+                main_repo
+                    .workdir()
+                    .unwrap_or_else(|| main_repo.path())
+                    .display()
+            );
+        }
+    }
+
+    for name in repo.worktrees()?.iter().flatten() {
+        let worktree = repo.find_worktree(name)?;
+
+        // NOTE: the working tree might have been removed already
+        if worktree.validate().is_err() {
+            let worktree_head =
+                fs::read_to_string(common_dir(repo).join("worktrees").join(name).join("HEAD"))
+                    .unwrap_or_default();
+            if worktree_head.trim_end() == format!("ref: {}", head) {
+                worktrees.push(worktree);
+            }
+            continue;
+        }
+
+        let worktree_repo = Repository::open_from_worktree(&worktree)?;
+        if worktree_repo
+            .head()
+            .ok()
+            .and_then(|x| x.name().map(|x| x == head))
+            != Some(true)
+        {
+            continue;
+        }
+
+        let mut options = StatusOptions::new();
+        options.include_untracked(true);
+        options.include_ignored(false);
+
+        if !worktree_repo.statuses(Some(&mut options))?.is_empty() {
+            bail!(
+                "branch is checked out in worktree {} which has uncommitted changes",
+                worktree.path().display()
+            );
+        }
+
+        worktrees.push(worktree);
+    }
+
+    Ok(worktrees)
+}
+
+/// Remove the worktrees (after confirmation) so the branch can be deleted.
+fn remove_worktrees(branch_name: &str, worktrees: Vec<Worktree>, yes: bool) -> Result<()> {
+    for worktree in worktrees {
+        let path = worktree.path().display().to_string();
+        if !yes
+            && !confirm(&format!(
+                "Branch {} is checked out in worktree {}. Remove the worktree? [y/N] ",
+                branch_name, path
+            ))?
+        {
+            bail!("branch is checked out in worktree {}", path);
+        }
+
+        worktree.prune(Some(
+            WorktreePruneOptions::new().valid(true).working_tree(true),
+        ))?;
+        println!("Worktree removed: {}", path);
+    }
+
+    Ok(())
+}
+
+/// Backup a deleted remote branch so it can be restored as a local branch.
+fn backup_remote_branch(repo: &Repository, remote_branch: &RemoteBranch) -> Result<()> {
+    backup_branch(
+        repo,
+        remote_branch.branch_name(),
+        remote_branch.oid,
+        Some(&remote_branch.tracking_ref),
+    )
+    .with_context(|| {
+        format!(
+            "branch deleted but could not be backed up (its tip was {})",
+            remote_branch.oid
+        )
+    })
+}
+
+struct DeletedBranch {
+    timestamp: i64,
+    oid: Oid,
+    branch_name: String,
+    upstream: Option<String>,
+}
+
+impl DeletedBranch {
+    /// Reference keeping the tip of the branch. The key is a hash of the deletion so the
+    /// backups of branches like foo and foo/bar don't collide.
+    fn backup_ref(&self) -> Result<String> {
+        let key = Oid::hash_object(
+            git2::ObjectType::Blob,
+            format!("{}\t{}\t{}", self.timestamp, self.oid, self.branch_name).as_bytes(),
+        )?;
+
+        Ok(format!("refs/git-tools/deleted/{}", key))
+    }
+
+    /// Check if the backup reference still exists (i.e. the branch has not been restored or
+    /// its backup has not expired).
+    fn is_restorable(&self, repo: &Repository) -> Result<bool> {
+        Ok(repo.refname_to_id(&self.backup_ref()?).ok() == Some(self.oid))
+    }
+}
+
+/// Keep the tip of the branch in refs/git-tools/deleted/ and record the deletion in
+/// .git/git-tools-deleted so the branch can be restored later on.
+fn backup_branch(
+    repo: &Repository,
+    branch_name: &str,
+    oid: Oid,
+    upstream: Option<&str>,
+) -> Result<()> {
+    let entry = DeletedBranch {
+        timestamp: now(),
+        oid,
+        branch_name: branch_name.to_string(),
+        upstream: upstream.map(|x| x.to_string()),
+    };
+
+    repo.reference(
+        &entry.backup_ref()?,
+        oid,
+        true,
+        &format!("git delete: {}", branch_name),
+    )?;
+
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(deleted_log_path(repo))
+        .context("Could not open the log of deleted branches")?;
+    writeln!(
+        log,
+        "{}\t{}\t{}\t{}",
+        entry.timestamp,
+        entry.oid,
+        entry.branch_name,
+        entry.upstream.unwrap_or_default()
+    )?;
+
+    Ok(())
+}
+
+/// Remove the backups older than delete.backup-expiry days (90 by default, 0 to keep them
+/// forever) along with their entries in the log of deleted branches.
+fn prune_deleted_branches(repo: &Repository) -> Result<()> {
+    let days = repo
+        .config()?
+        .get_i64("delete.backup-expiry")
+        .unwrap_or(DEFAULT_BACKUP_EXPIRY);
+    if days <= 0 {
+        return Ok(());
+    }
+
+    let deadline = now() - days * 86400;
+    let entries = read_deleted_log(repo)?;
+    if entries.iter().all(|x| x.timestamp >= deadline) {
+        return Ok(());
+    }
+
+    let mut log = String::new();
+    for entry in entries {
+        if entry.timestamp >= deadline {
+            log.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                entry.timestamp,
+                entry.oid,
+                entry.branch_name,
+                entry.upstream.unwrap_or_default()
+            ));
+        } else if let Ok(mut reference) = repo.find_reference(&entry.backup_ref()?) {
+            reference.delete()?;
+        }
+    }
+
+    fs::write(deleted_log_path(repo), log)
+        .context("Could not write the log of deleted branches")?;
+
+    Ok(())
+}
+
+const DEFAULT_BACKUP_EXPIRY: i64 = 90;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before UNIX epoch")
+        .as_secs() as i64
+}
+
+/// Log of the deleted branches. It is in the common directory of the repository so it is
+/// shared by all the worktrees like the backup references.
+fn deleted_log_path(repo: &Repository) -> PathBuf {
+    common_dir(repo).join("git-tools-deleted")
+}
+
+/// The directory shared by all the worktrees (.git), even when the repository is opened from a
+/// linked worktree (.git/worktrees/<name>).
+fn common_dir(repo: &Repository) -> PathBuf {
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(path) => repo.path().join(path.trim_end()),
+        Err(_) => repo.path().to_path_buf(),
+    }
+}
+
+fn read_deleted_log(repo: &Repository) -> Result<Vec<DeletedBranch>> {
+    let content = match fs::read_to_string(deleted_log_path(repo)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context("Could not read the log of deleted branches"),
+    };
+
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some(DeletedBranch {
+                timestamp: fields.next()?.parse().ok()?,
+                oid: Oid::from_str(fields.next()?).ok()?,
+                branch_name: fields.next()?.to_string(),
+                upstream: fields
+                    .next()
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string()),
+            })
+        })
+        .collect())
+}
+
+/// Find the last deletion recorded for the branch that can still be restored.
+fn find_deleted_branch(repo: &Repository, branch_name: &str) -> Result<Option<DeletedBranch>> {
+    for entry in read_deleted_log(repo)?.into_iter().rev() {
+        if entry.branch_name == branch_name && entry.is_restorable(repo)? {
+            return Ok(Some(entry));
+        }
+    }
+
+    Ok(None)
+}
+
+fn list_deleted_branches(repo: &Repository) -> Result<()> {
+    let mut deleted = Vec::new();
+    for entry in read_deleted_log(repo)? {
+        if entry.is_restorable(repo)? {
+            deleted.push(entry);
+        }
+    }
+
+    if deleted.is_empty() {
+        println!("There is no deleted branch to restore.");
+        return Ok(());
+    }
+
+    deleted.sort_by_key(|x| -x.timestamp);
+    for entry in deleted {
+        let mut line = format!(
+            "{} {} (deleted on {} {:02}:{:02} UTC)",
+            &entry.oid.to_string()[..7],
+            entry.branch_name,
+            common::format_date(entry.timestamp),
+            entry.timestamp.rem_euclid(86400) / 3600,
+            entry.timestamp.rem_euclid(3600) / 60
+        );
+        if let Some(upstream) = entry.upstream.as_deref() {
+            line.push_str(&format!(" upstream: {}", upstream));
         }
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// Recreate a deleted branch from its backup reference and set its upstream back.
+fn restore_branch(repo: &Repository, branch_name: &str, push_upstream: bool) -> Result<()> {
+    let entry = find_deleted_branch(repo, branch_name)?
+        .with_context(|| format!("Could not find deleted branch: {}", branch_name))?;
+    let commit = repo.find_commit(entry.oid)?;
+    repo.branch(branch_name, &commit, false)
+        .with_context(|| format!("Could not create branch {}", branch_name))?;
+    println!(
+        "Local branch restored: {} ({})",
+        branch_name,
+        &entry.oid.to_string()[..7]
+    );
 
-        // TODO better handling for credentials using git2_credentials
-        //      make sure it works with ~/.ssh/id_rsa and ssh-agent
-        let mut remote_callbacks = git2::RemoteCallbacks::new();
-        let mut handler = common::CredentialHandler::new();
-        remote_callbacks.credentials(move |x, y, z| handler.credentials_callback(x, y, z));
+    if let Some(upstream) = entry.upstream.as_deref() {
+        let remote_name = repo
+            .branch_remote_name(upstream)
+            .ok()
+            .and_then(|x| x.as_str().map(|x| x.to_string()))
+            .with_context(|| format!("Could not find the remote of {}", upstream))?;
+        let upstream_branch_name = upstream
+            .strip_prefix(&format!("refs/remotes/{}/", remote_name))
+            .with_context(|| format!("Could not find the remote branch of {}", upstream))?;
 
-        let mut push_options = git2::PushOptions::new();
-        push_options.remote_callbacks(remote_callbacks);
+        if push_upstream {
+            let refspec = format!(
+                "refs/heads/{}:refs/heads/{}",
+                branch_name, upstream_branch_name
+            );
+            for (refname, message) in common::push(repo, &remote_name, &[&refspec])? {
+                if let Some(message) = message {
+                    bail!("The remote rejected {}: {}", refname, message);
+                }
+            }
+            println!("Upstream restored: {}", upstream);
+        }
 
-        remote.push(
-            &[&format!("+:refs/heads/{}", branch_name)],
-            Some(&mut push_options),
+        // NOTE: the upstream is configured even if the remote branch does not exist anymore
+        //       so the branch is restored as it was
+        let mut config = repo.config()?;
+        config.set_str(&format!("branch.{}.remote", branch_name), &remote_name)?;
+        config.set_str(
+            &format!("branch.{}.merge", branch_name),
+            &format!("refs/heads/{}", upstream_branch_name),
         )?;
-        println!("Upstream deleted: {}", upstream_name);
+        println!("Upstream set to {}/{}.", remote_name, upstream_branch_name);
+
+        if repo.find_reference(upstream).is_err() {
+            println!(
+                "The upstream {} does not exist anymore, use --push to push it again.",
+                upstream
+            );
+        }
+    }
+
+    repo.find_reference(&entry.backup_ref()?)?.delete()?;
+
+    Ok(())
+}
+
+/// Find the local branches merged into the default branch (--merged) or whose upstream branch
+/// does not exist anymore (--gone) and delete them after confirmation.
+fn delete_stale_branches(repo: &Repository, params: &Delete) -> Result<()> {
+    let gone_upstreams = if params.gone {
+        get_gone_upstreams(repo, params.dry_run)?
+    } else {
+        HashSet::new()
+    };
+
+    let mut selected = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let branch_name = branch
+            .name()
+            .context("Could not retrieve branch name")?
+            .expect("not valid utf-8")
+            .to_owned();
+
+        if branch.is_head() || common::get_protected_rule(repo, &branch_name)?.is_some() {
+            continue;
+        }
+
+        let remote_name =
+            get_upstream_remote_name(repo, &branch).unwrap_or_else(|| "origin".to_string());
+        let default_branch = common::get_default_branch(repo, &remote_name)
+            .context("Could not find default branch for this repository")?;
+        if default_branch.strip_prefix(&format!("{}/", remote_name)) == Some(branch_name.as_str()) {
+            continue;
+        }
+
+        let merged =
+            matches!(get_unmerged(repo, &branch), Ok((_, unmerged)) if unmerged.is_empty());
+        let gone = repo
+            .branch_upstream_name(&format!("refs/heads/{}", branch_name))
+            .ok()
+            .and_then(|x| x.as_str().map(|x| gone_upstreams.contains(x)))
+            .unwrap_or(false);
+
+        if params.merged && merged {
+            selected.push((branch_name, "merged"));
+        } else if params.gone && gone {
+            if merged || params.force {
+                selected.push((branch_name, "upstream gone"));
+            } else {
+                println!(
+                    "Skipped {}: upstream gone but not fully merged (use --force to delete it anyway)",
+                    branch_name
+                );
+            }
+        }
+    }
+
+    if selected.is_empty() {
+        println!("There is no branch to delete.");
+        return Ok(());
+    }
+
+    if !params.dry_run {
+        println!("The following branches will be deleted:");
+        for (branch_name, reason) in selected.iter() {
+            println!("  {} ({})", branch_name, reason);
+        }
+
+        if !params.yes && !confirm("Delete these branches? [y/N] ")? {
+            println!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    delete_branches(
+        repo,
+        selected
+            .into_iter()
+            .map(|(branch_name, reason)| (branch_name, reason.to_string()))
+            .collect(),
+        BranchType::Local,
+        params,
+        &gone_upstreams,
+        Vec::new(),
+    )
+}
+
+/// The remote-tracking references of the upstream branches that have been deleted from their
+/// remote. The remotes are fetched with prune, or only listed in dry-run so nothing changes in
+/// the repository.
+fn get_gone_upstreams(repo: &Repository, dry_run: bool) -> Result<HashSet<String>> {
+    // NOTE: only the upstream branches that exist before pruning can be gone, the others have
+    //       never been pushed (e.g. git fork --track push)
+    let mut tracked: BTreeMap<String, Vec<RemoteBranch>> = BTreeMap::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let remote_branch = branch.upstream().ok().and_then(|x| {
+            RemoteBranch::from_tracking_ref(repo, x.get().name().expect("not valid utf-8")).ok()
+        });
+        if let Some(remote_branch) = remote_branch {
+            tracked
+                .entry(remote_branch.remote_name.clone())
+                .or_default()
+                .push(remote_branch);
+        }
+    }
+
+    let mut gone = HashSet::new();
+    for (remote_name, remote_branches) in tracked {
+        if dry_run {
+            let refs = common::list_remote_refs(repo, &remote_name, git2::Direction::Fetch)
+                .with_context(|| format!("Could not list remote `{}`", remote_name))?;
+            gone.extend(
+                remote_branches
+                    .into_iter()
+                    .filter(|x| !refs.iter().any(|(refname, _)| *refname == x.remote_ref))
+                    .map(|x| x.tracking_ref),
+            );
+        } else {
+            common::fetch_prune(repo, &remote_name)
+                .with_context(|| format!("Could not fetch remote `{}`", remote_name))?;
+            gone.extend(
+                remote_branches
+                    .into_iter()
+                    .filter(|x| repo.find_reference(&x.tracking_ref).is_err())
+                    .map(|x| x.tracking_ref),
+            );
+        }
     }
 
-    branch.delete();
-    println!("Local branch deleted: {}", branch_name);
+    Ok(gone)
+}
+
+fn get_upstream_remote_name(repo: &Repository, branch: &Branch) -> Option<String> {
+    let refname = branch.get().name()?;
+
+    repo.branch_upstream_remote(refname)
+        .ok()
+        .and_then(|x| x.as_str().map(|x| x.to_string()))
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{}", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Make sure the branch does not match any rule of git-tools.protected.
+fn check_protected(repo: &Repository, branch_name: &str) -> Result<()> {
+    if let Some(rule) = common::get_protected_rule(repo, branch_name)? {
+        bail!(
+            "branch {} is protected by the rule `{}` (git-tools.protected)",
+            branch_name,
+            rule
+        );
+    }
 
     Ok(())
 }
+
+/// Make sure all the commits of the branch have landed in the default branch. Returns the
+/// default branch.
+fn check_merged(repo: &Repository, branch: &Branch) -> Result<String> {
+    let branch_name = branch
+        .name()
+        .context("Could not retrieve branch name")?
+        .expect("not valid utf-8");
+    let (default_branch, unmerged) = get_unmerged(repo, branch)?;
+
+    if !unmerged.is_empty() {
+        let mut message = format!(
+            "branch {} is not fully merged into {}. Unmerged commits:\n",
+            branch_name, default_branch
+        );
+        for oid in unmerged {
+            let commit = repo.find_commit(oid)?;
+            message.push_str(&format!(
+                "{} {}\n",
+                &oid.to_string()[..7],
+                commit.summary().unwrap_or_default()
+            ));
+        }
+        message.push_str("Use --force to delete it anyway.");
+
+        bail!(message);
+    }
+
+    Ok(default_branch)
+}
+
+/// Returns the default branch and the commits of the branch that have not landed in it. The
+/// list is empty if the branch has been squash-merged.
+fn get_unmerged(repo: &Repository, branch: &Branch) -> Result<(String, Vec<Oid>)> {
+    let branch_name = branch
+        .name()
+        .context("Could not retrieve branch name")?
+        .expect("not valid utf-8");
+    let branch_ref = branch.get().name().expect("not valid utf-8");
+    let remote_name = if branch.get().is_remote() {
+        repo.branch_remote_name(branch_ref)
+            .ok()
+            .and_then(|x| x.as_str().map(|x| x.to_string()))
+    } else {
+        get_upstream_remote_name(repo, branch)
+    }
+    .unwrap_or_else(|| "origin".to_string());
+    let default_branch = common::get_default_branch(repo, &remote_name)
+        .context("Could not find default branch for this repository")?;
+
+    if repo.revparse_single(&default_branch).is_err() {
+        bail!(
+            "could not find the default branch {} to check if {} has been merged \
+            (use --force to delete it anyway)",
+            default_branch,
+            branch_name
+        );
+    }
+
+    let unmerged = common::get_unmerged_commits(repo, branch_ref, &default_branch)?;
+
+    if !unmerged.is_empty() && common::is_squash_merged(repo, branch_ref, &default_branch)? {
+        return Ok((default_branch, Vec::new()));
+    }
+
+    Ok((default_branch, unmerged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branch_config() {
+        assert!(is_branch_config("branch.v1.merge", "v1"));
+        assert!(is_branch_config("branch.v1.git-tools-parent", "v1"));
+        assert!(is_branch_config("branch.v1.2.remote", "v1.2"));
+        assert!(!is_branch_config("branch.v1.2.remote", "v1"));
+        assert!(!is_branch_config("branch.v1.2.git-tools-parent", "v1"));
+        assert!(!is_branch_config("branch.v10.merge", "v1"));
+        assert!(!is_branch_config("remote.v1.url", "v1"));
+    }
+}
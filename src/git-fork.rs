@@ -1,9 +1,11 @@
 mod common;
 
-use common::Git;
+use common::{BranchType, Git};
 
+use std::cmp::Ordering;
 use std::env;
 use std::io::Write;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -12,6 +14,64 @@ use structopt::StructOpt;
     about = env!("CARGO_PKG_DESCRIPTION")
 )]
 pub struct Fork {
+    /// Create the branch in a new linked worktree instead of switching to it.
+    ///
+    /// The worktree is created in a sibling directory named after the branch unless a path is
+    /// given: --worktree=<path>
+    #[structopt(long, require_equals = true)]
+    worktree: Option<Option<PathBuf>>,
+
+    /// Carry the uncommitted changes (including untracked files) to the new branch.
+    ///
+    /// The changes are stashed and re-applied on the new branch. If they cannot be re-applied
+    /// cleanly, the conflicts are left in the working tree and the stash is kept.
+    #[structopt(long, conflicts_with = "worktree")]
+    carry: bool,
+
+    /// Set the upstream of the new branch.
+    ///
+    /// "push" tracks the future remote branch of the same name (origin/<branch_name>), "base"
+    /// tracks the branch it is created from and "none" doesn't set any upstream.
+    ///
+    /// You can make this behavior the default using the following command:
+    ///
+    /// git config --global fork.track push
+    #[structopt(long, possible_values = &["push", "base", "none"])]
+    track: Option<String>,
+
+    /// Use the branch name as is: do not apply the template nor normalize it.
+    ///
+    /// Otherwise the branch name is normalized (e.g. "Fix login bug #123" becomes
+    /// "fix-login-bug-123") and the template is applied if any:
+    ///
+    /// git config fork.template "{user}/{date}-{name}"
+    #[structopt(long)]
+    raw: bool,
+
+    /// Create the branch from a pull request (or merge request on GitLab) of origin.
+    #[structopt(long, conflicts_with = "from")]
+    pr: Option<u32>,
+
+    /// Create the branch from the highest version tag matching the pattern (e.g. "v1.4.*").
+    ///
+    /// The tags are fetched from origin first.
+    #[structopt(long, conflicts_with_all = &["from", "pr"])]
+    from_tag: Option<String>,
+
+    /// Create the branch from the current branch and record it as its parent (stacked branches).
+    ///
+    /// The parent is stored in the configuration: branch.<branch_name>.git-tools-parent
+    #[structopt(long, conflicts_with_all = &["from", "pr", "from-tag"])]
+    stack: bool,
+
+    /// Reset the branch if it already exists (or create it even if origin/<branch_name> exists).
+    #[structopt(long, short = "f")]
+    force: bool,
+
+    /// Switch to the branch if it already exists (locally or on origin) instead of failing.
+    #[structopt(long, conflicts_with = "force")]
+    checkout_existing: bool,
+
     branch_name: String,
     from: Option<String>,
 }
@@ -38,33 +98,422 @@ fn execute() -> i32 {
 }
 
 pub fn run(params: Fork) -> Result<(), Box<dyn std::error::Error>> {
+    // NOTE: Git::open() changes the current directory to the root of the repository
+    let worktree_path = match params.worktree.as_ref() {
+        Some(Some(path)) => Some(Some(env::current_dir()?.join(path))),
+        Some(None) => Some(None),
+        None => None,
+    };
+
     let mut git = Git::open()?;
 
-    if git.has_file_changes()? {
+    // NOTE: the current working tree is not touched when using a worktree
+    if worktree_path.is_none() && !params.carry && git.has_file_changes()? {
         return Err("The repository has not committed changes, aborting.".into());
     }
 
-    let branch_name = params.branch_name.as_str();
-    let default_branch = git.get_default_branch("origin")?;
-    let name = params
-        .from
-        .as_deref()
-        .unwrap_or_else(|| default_branch.as_str());
+    // NOTE: an existing branch is found by the name given by the user, before normalizing it
+    //       and applying the template (which might contain a date)
+    let branch_name = if branch_exists(&git, &params.branch_name) {
+        params.branch_name.clone()
+    } else {
+        make_branch_name(&git, &params.branch_name, params.raw)?
+    };
+    let branch_name = branch_name.as_str();
+    let tracking = if use_existing_branch(&mut git, &params, branch_name)? {
+        None
+    } else {
+        create_branch(&mut git, &params, branch_name)?
+    };
+
+    let mut stashed = false;
+    if let Some(path) = worktree_path {
+        let path = match path {
+            Some(path) => path,
+            None => default_worktree_path(&git, branch_name)?,
+        };
+        let worktree = git.add_worktree(branch_name, &path)?;
+
+        println!(
+            "Branch {} checked out in worktree: {}",
+            branch_name,
+            worktree.path().display()
+        );
+    } else {
+        if params.carry {
+            stashed = git
+                .stash_save(&format!("git-fork: carry changes to {}", branch_name))?
+                .is_some();
+        }
+
+        git.switch_branch(branch_name)?;
+
+        println!("Switched to branch {}.", branch_name);
+    }
+
+    if let Some(tracking) = tracking {
+        println!("{}", tracking);
+    }
 
-    if name.contains('/') {
-        git.update_upstream(name)?;
+    if stashed {
+        match git.stash_pop() {
+            Ok(true) => println!("Uncommitted changes carried over."),
+            Ok(false) => {
+                return Err(
+                    "The uncommitted changes have been re-applied with conflicts. \
+                    The changes are still in the stash (stash@{0})."
+                        .into(),
+                )
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Could not re-apply the uncommitted changes: {}\n\
+                    The changes are still in the stash (stash@{{0}}).",
+                    err
+                )
+                .into())
+            }
+        }
     }
 
+    Ok(())
+}
+
+fn branch_exists(git: &Git, branch_name: &str) -> bool {
+    git.repo.find_branch(branch_name, BranchType::Local).is_ok()
+        || git
+            .repo
+            .find_branch(&format!("origin/{}", branch_name), BranchType::Remote)
+            .is_ok()
+}
+
+/// Check if the branch already exists locally or on origin. Returns true if the existing branch
+/// should be used instead of creating a new one.
+fn use_existing_branch(
+    git: &mut Git,
+    params: &Fork,
+    branch_name: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let remote_branch_name = format!("origin/{}", branch_name);
+
+    if let Ok(branch) = git.repo.find_branch(branch_name, BranchType::Local) {
+        if params.checkout_existing {
+            return Ok(true);
+        } else if !params.force {
+            return Err(format!(
+                "Branch {} already exists. Use --checkout-existing to switch to it or \
+                --force to reset it.",
+                branch_name
+            )
+            .into());
+        } else if branch.is_head() {
+            return Err(format!(
+                "Branch {} already exists and is the current branch, it cannot be reset.",
+                branch_name
+            )
+            .into());
+        }
+    } else if git
+        .repo
+        .find_branch(&remote_branch_name, BranchType::Remote)
+        .is_ok()
+    {
+        if params.checkout_existing {
+            git.update_upstream(&remote_branch_name)?;
+            git.branch(branch_name, Some(&remote_branch_name), false)?;
+            git.set_upstream(branch_name, &remote_branch_name)?;
+            println!(
+                "Branch {} created from {}.",
+                branch_name, remote_branch_name
+            );
+
+            return Ok(true);
+        } else if !params.force {
+            return Err(format!(
+                "A remote branch {} already exists. Use --checkout-existing to work on it or \
+                --force to create a new branch anyway.",
+                remote_branch_name
+            )
+            .into());
+        }
+    }
+
+    Ok(false)
+}
+
+/// Create the branch from the default branch (or the revision given by the user) and set its
+/// upstream. Returns a message describing the upstream that has been set if any.
+fn create_branch(
+    git: &mut Git,
+    params: &Fork,
+    branch_name: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let default_branch = git.get_default_branch("origin")?;
+    let pull_request_ref;
+    let tag;
+    let name = if params.stack {
+        git.branch_name
+            .as_deref()
+            .ok_or("Cannot stack a branch: HEAD is detached.")?
+    } else if let Some(number) = params.pr {
+        pull_request_ref = git.fetch_pull_request("origin", number)?;
+        pull_request_ref.as_str()
+    } else if let Some(pattern) = params.from_tag.as_deref() {
+        git.fetch_tags("origin")?;
+        tag = find_highest_tag(git, pattern)?;
+        tag.as_str()
+    } else {
+        let name = params.from.as_deref().unwrap_or(default_branch.as_str());
+
+        if name.contains('/') {
+            git.update_upstream(name)?;
+        }
+
+        name
+    };
+
+    let exists = git.repo.find_branch(branch_name, BranchType::Local).is_ok();
+
     match git.get_branch_hash(name)? {
         // name is really a branch
-        Some(hash) => git.branch(branch_name, Some(hash.as_str()))?,
+        Some(hash) => git.branch(branch_name, Some(hash.as_str()), params.force)?,
         // name was not a branch
-        None => git.branch(branch_name, Some(name))?,
+        None => git.branch(branch_name, Some(name), params.force)?,
     };
 
-    git.switch_branch(branch_name)?;
+    if exists {
+        println!("Branch {} reset to {}.", branch_name, name);
+    } else {
+        println!("Branch {} created.", branch_name);
+    }
 
-    println!("Branch {} created.", branch_name);
+    if params.stack {
+        git.set_parent_branch(branch_name, name)?;
+        println!("Branch {} stacked on {}.", branch_name, name);
+    }
 
-    Ok(())
+    let track = match params.track.clone() {
+        Some(track) => Some(track),
+        None => git.config.get_string("fork.track").ok(),
+    };
+
+    Ok(match track.as_deref() {
+        Some("push") => {
+            git.set_push_tracking(branch_name, "origin")?;
+            Some(format!("Upstream set to origin/{}.", branch_name))
+        }
+        Some("base") => {
+            if git.set_upstream(branch_name, name).is_ok() {
+                Some(format!("Upstream set to {}.", name))
+            } else {
+                Some(format!(
+                    "Warning: {} is not a branch, no upstream has been set.",
+                    name
+                ))
+            }
+        }
+        Some("none") | None => None,
+        Some(other) => return Err(format!("Invalid value for fork.track: {}", other).into()),
+    })
+}
+
+fn find_highest_tag(git: &Git, pattern: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let tags = git.repo.tag_names(Some(pattern))?;
+
+    tags.iter()
+        .flatten()
+        .filter_map(|tag| parse_version(tag).map(|version| (version, tag)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, tag)| tag.to_string())
+        .ok_or_else(|| format!("Could not find any version tag matching: {}", pattern).into())
+}
+
+/// A version parsed from a tag, ordered according to semver. Pre-releases are lower than the
+/// release itself.
+#[derive(Debug, PartialEq, Eq)]
+struct Version {
+    /// The numbers of the version without trailing zeros (1.4.0 is the same as 1.4).
+    numbers: Vec<u64>,
+    pre_release: Vec<Identifier>,
+}
+
+/// Identifier of a pre-release. Numeric identifiers are lower than alphanumeric ones.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.numbers.cmp(&other.numbers).then_with(|| {
+            match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre_release.cmp(&other.pre_release),
+            }
+        })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Parse a version tag like "v1.4.2" or "1.5.0-rc.1".
+fn parse_version(tag: &str) -> Option<Version> {
+    let version = tag.trim_start_matches(|c: char| !c.is_ascii_digit());
+    // NOTE: build metadata is ignored
+    let version = version.split('+').next().unwrap_or_default();
+    let mut parts = version.splitn(2, '-');
+    let mut numbers = parts
+        .next()?
+        .split('.')
+        .map(|x| x.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    while numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    let pre_release = match parts.next() {
+        Some(pre_release) => pre_release
+            .split('.')
+            .map(|x| match x.parse() {
+                Ok(number) if x.bytes().all(|c| c.is_ascii_digit()) => Identifier::Numeric(number),
+                _ => Identifier::Alphanumeric(x.to_string()),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Some(Version {
+        numbers,
+        pre_release,
+    })
+}
+
+fn default_worktree_path(
+    git: &Git,
+    branch_name: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let workdir = git
+        .repo
+        .workdir()
+        .ok_or("Cannot create a worktree from a bare repository.")?
+        .canonicalize()?;
+    let parent = workdir
+        .parent()
+        .ok_or("Cannot create a worktree next to the root directory.")?;
+
+    Ok(parent.join(branch_name.replace('/', "-")))
+}
+
+fn make_branch_name(
+    git: &Git,
+    input: &str,
+    raw: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let name = if raw {
+        input.to_string()
+    } else {
+        let name = slugify(input);
+        if name.is_empty() {
+            return Err(format!("Invalid branch name: {:?}", input).into());
+        }
+
+        match git.config.get_string("fork.template") {
+            Ok(template) => {
+                let user = users::get_current_username().ok_or("Could not get username.")?;
+
+                template
+                    .replace("{user}", &slugify(&user.to_string_lossy()))
+                    .replace("{date}", &today())
+                    .replace("{name}", &name)
+            }
+            Err(_) => name,
+        }
+    };
+
+    if !git2::Branch::name_is_valid(&name)? {
+        return Err(format!("Invalid branch name: {:?}", name).into());
+    }
+
+    Ok(name)
+}
+
+/// Turn free text into something usable in a branch name: lowercase words separated by dashes.
+/// Slashes are kept to allow namespaces.
+fn slugify(text: &str) -> String {
+    text.split('/')
+        .map(|part| {
+            part.to_lowercase()
+                .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Current date (UTC) in the format YYYY-MM-DD.
+fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system time is before UNIX epoch")
+        .as_secs() as i64;
+
+    common::format_date(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highest<'a>(tags: &[&'a str]) -> &'a str {
+        tags.iter()
+            .copied()
+            .max_by_key(|tag| parse_version(tag).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_version_numbers() {
+        assert_eq!(highest(&["v1.4.2", "v1.10.0", "v1.9.9"]), "v1.10.0");
+        assert_eq!(parse_version("v1.4"), parse_version("1.4.0"));
+        assert!(parse_version("v1.4") < parse_version("v1.4.1"));
+        assert_eq!(parse_version("release"), None);
+        assert_eq!(parse_version("v1.x"), None);
+    }
+
+    #[test]
+    fn parse_version_pre_release() {
+        assert!(parse_version("v1.5.0-rc.1") < parse_version("v1.5.0"));
+        assert!(parse_version("v1.5.0-rc.9") < parse_version("v1.5.0-rc.10"));
+        assert!(parse_version("v1.5.0-alpha") < parse_version("v1.5.0-alpha.1"));
+        assert!(parse_version("v1.5.0-alpha.1") < parse_version("v1.5.0-alpha.beta"));
+        assert!(parse_version("v1.5.0-beta.11") < parse_version("v1.5.0-rc.1"));
+        assert!(parse_version("v1.4.9") < parse_version("v1.5.0-rc.1"));
+        assert_eq!(parse_version("v1.5.0+build.1"), parse_version("v1.5.0"));
+    }
+
+    #[test]
+    fn slugify_text() {
+        assert_eq!(slugify("Fix the Login page"), "fix-the-login-page");
+        assert_eq!(
+            slugify("  Add  support for v1.2_beta!  "),
+            "add-support-for-v1.2_beta"
+        );
+        assert_eq!(
+            slugify("JIRA-123: Crash on start"),
+            "jira-123-crash-on-start"
+        );
+        assert_eq!(slugify("Feature/New Thing"), "feature/new-thing");
+        assert_eq!(slugify("fix//double / slash/"), "fix/double/slash");
+        assert_eq!(slugify("Été à Paris"), "été-à-paris");
+        assert_eq!(slugify("?!"), "");
+    }
 }
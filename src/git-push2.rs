@@ -1,21 +1,55 @@
 mod common;
 
-use common::Git;
+use common::{BranchType, Git};
 
+use std::collections::BTreeMap;
 use std::env;
 use std::io::Write;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Command;
-use structopt::{clap::AppSettings, StructOpt};
+use structopt::{clap::ErrorKind, StructOpt};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
     bin_name = "git push2",
     about = env!("CARGO_PKG_DESCRIPTION"),
-    settings = &[AppSettings::TrailingVarArg, AppSettings::AllowLeadingHyphen],
 )]
 pub struct Params {
-    args: Vec<String>,
+    /// Force push safely.
+    ///
+    /// The push is refused if the remote branch has been updated since the last fetch
+    /// (--force-with-lease) or if the commits of the remote branch have never been integrated
+    /// in the local branch (--force-if-includes).
+    #[structopt(long, short = "f")]
+    force: bool,
+
+    /// Skip the safety checks made before pushing.
+    ///
+    /// The checks can be configured individually:
+    ///
+    /// git config push2.check-default-branch false
+    ///
+    /// git config push2.check-wip-commits false
+    ///
+    /// git config push2.max-file-size 20m (0 to disable)
+    #[structopt(long)]
+    no_verify_tools: bool,
+
+    /// Create the push remote if it doesn't exist.
+    ///
+    /// The URL is made from a template where {repo} is replaced by the name of the repository:
+    ///
+    /// git config --global git-tools.push-remote-url "git@github.com:me/{repo}.git"
+    #[structopt(long)]
+    create_remote: bool,
+
+    /// Push all the local branches that have commits that are not in the default branch.
+    #[structopt(long, conflicts_with = "branch-names")]
+    all_mine: bool,
+
+    /// Branches to push (the current branch by default).
+    branch_names: Vec<String>,
 }
 
 fn main() {
@@ -28,7 +62,16 @@ const SUCCESS: i32 = 0;
 const FAILURE: i32 = 1;
 
 fn execute() -> i32 {
-    let opts = Params::from_args();
+    let opts = match Params::from_iter_safe(env::args()) {
+        Ok(opts) => opts,
+        // NOTE: the arguments unknown to git-push2 (e.g. --tags or -o ci.skip) are for git push
+        Err(err) if err.kind == ErrorKind::UnknownArgument => {
+            eprintln!("{}", git_push(env::args().skip(1).collect()));
+
+            return FAILURE;
+        }
+        Err(err) => err.exit(),
+    };
 
     if let Err(err) = run(opts) {
         eprintln!("{}", err);
@@ -42,17 +85,733 @@ fn execute() -> i32 {
 pub fn run(params: Params) -> Result<(), Box<dyn std::error::Error>> {
     let git = Git::open()?;
 
-    Err(match (git.branch_name.as_ref(), git.upstream.as_ref()) {
-        (Some(name), None) => Command::new("git")
-            .arg("push")
-            .args(&["--set-upstream", "origin", name])
-            .args(params.args)
-            .exec()
-            .into(),
-        _ => Command::new("git")
-            .arg("push")
-            .args(params.args)
-            .exec()
-            .into(),
-    })
+    // NOTE: git push2 <remote> [<refspec>...] is forwarded to git push
+    if let Some(name) = params.branch_names.first() {
+        if git.repo.find_remote(name).is_ok() {
+            return Err(git_push(env::args().skip(1).collect()));
+        }
+    }
+
+    let branch_names = if params.all_mine {
+        get_my_branches(&git)?
+    } else if !params.branch_names.is_empty() {
+        params.branch_names.clone()
+    } else {
+        vec![git
+            .branch_name
+            .clone()
+            .ok_or("Cannot push: HEAD is detached.")?]
+    };
+
+    if branch_names.is_empty() {
+        println!("There is no branch to push.");
+        return Ok(());
+    }
+
+    // NOTE: the branches are grouped by remote to push them all at once
+    let mut remotes: BTreeMap<String, Vec<BranchPush>> = BTreeMap::new();
+    for branch_name in branch_names {
+        git.repo
+            .find_branch(&branch_name, BranchType::Local)
+            .map_err(|_| format!("Could not find local branch: {}", branch_name))?;
+
+        let fetch_remote_name = match git
+            .config
+            .get_string(&format!("branch.{}.remote", branch_name))
+        {
+            // NOTE: "." means the upstream is a local branch
+            Ok(remote_name) if remote_name != "." => remote_name,
+            _ => "origin".to_string(),
+        };
+        let (remote_name, push_remote_configured) = get_push_remote(&git, &branch_name);
+        let remote_name = remote_name.unwrap_or_else(|| fetch_remote_name.clone());
+
+        if git.repo.find_remote(&remote_name).is_err() {
+            if params.create_remote {
+                create_push_remote(&git, &remote_name, &fetch_remote_name)?;
+            } else {
+                return Err(format!(
+                    "Remote {} does not exist. Use --create-remote to create it from the URL \
+                    template (git-tools.push-remote-url).",
+                    remote_name
+                )
+                .into());
+            }
+        }
+
+        remotes.entry(remote_name).or_default().push(BranchPush {
+            branch_name,
+            fetch_remote_name,
+            push_remote_configured,
+        });
+    }
+
+    let mut rejected = 0;
+    for (remote_name, branches) in remotes {
+        let outcomes = push_branches(&git, &params, &remote_name, &branches)?;
+
+        for (branch, outcome) in branches.iter().zip(outcomes) {
+            let branch_name = branch.branch_name.as_str();
+            let remote_branch_name = format!("{}/{}", remote_name, branch_name);
+
+            match outcome {
+                Outcome::Rejected(message) => {
+                    eprintln!("Branch {} rejected: {}", branch_name, message);
+                    rejected += 1;
+                    continue;
+                }
+                Outcome::UpToDate => {
+                    println!(
+                        "Branch {} is up-to-date with {}.",
+                        branch_name, remote_branch_name
+                    );
+                }
+                Outcome::Created => println!(
+                    "Branch {} pushed to {} (new branch).",
+                    branch_name, remote_branch_name
+                ),
+                Outcome::FastForwarded => println!(
+                    "Branch {} pushed to {} (fast-forward).",
+                    branch_name, remote_branch_name
+                ),
+                Outcome::Forced => println!(
+                    "Branch {} pushed to {} (forced update).",
+                    branch_name, remote_branch_name
+                ),
+            }
+
+            let mut local_branch = git.repo.find_branch(branch_name, BranchType::Local)?;
+            if remote_name != branch.fetch_remote_name {
+                // NOTE: triangular workflow: the upstream stays on the fetch remote
+                if !branch.push_remote_configured {
+                    git.repo
+                        .config()?
+                        .set_str(&format!("branch.{}.pushRemote", branch_name), &remote_name)?;
+                    println!("Push remote set to {}.", remote_name);
+                }
+            } else if local_branch.upstream().is_err() {
+                local_branch.set_upstream(Some(&remote_branch_name))?;
+                println!("Upstream set to {}.", remote_branch_name);
+            }
+
+            if outcome == Outcome::Created {
+                if let Some(url) =
+                    git.get_pull_request_url(&branch.fetch_remote_name, &remote_name, branch_name)?
+                {
+                    println!("Create a pull request: {}", url);
+                }
+            }
+        }
+    }
+
+    if rejected > 0 {
+        return Err(format!("{} branch(es) could not be pushed.", rejected).into());
+    }
+
+    Ok(())
+}
+
+/// Replace the current process by git push with the arguments given after making the same
+/// checks as git-push2. Returns only if the arguments are refused or if the command could not be
+/// executed.
+fn git_push(args: Vec<String>) -> Box<dyn std::error::Error> {
+    let mut command = match make_git_push(args) {
+        Ok(command) => command,
+        Err(err) => return err,
+    };
+
+    command.exec().into()
+}
+
+fn make_git_push(args: Vec<String>) -> Result<Command, Box<dyn std::error::Error>> {
+    let git = Git::open()?;
+    let args = ForwardedArgs::parse(args)?;
+
+    // NOTE: the local branch pushed (if any) and the name of the remote branch
+    let mut updates = Vec::new();
+    if args.refspecs.is_empty() {
+        if let Some(branch_name) = git.branch_name.as_ref() {
+            updates.push((Some(branch_name.clone()), branch_name.clone()));
+        }
+    }
+    for refspec in args.refspecs.iter() {
+        let (src, dst) = match refspec.split_once(':') {
+            Some((src, dst)) => (src, Some(dst)),
+            None if args.delete => ("", Some(refspec.as_str())),
+            None => (refspec.as_str(), None),
+        };
+        let src = match src {
+            "" => None,
+            "HEAD" => git.branch_name.clone(),
+            src => Some(src.trim_start_matches("refs/heads/").to_string()),
+        }
+        .filter(|x| git.repo.find_branch(x, BranchType::Local).is_ok());
+        let dst = match dst {
+            Some(dst) if dst.starts_with("refs/") && !dst.starts_with("refs/heads/") => continue,
+            Some(dst) => dst.trim_start_matches("refs/heads/").to_string(),
+            None => match src.as_ref() {
+                Some(src) => src.clone(),
+                None => continue,
+            },
+        };
+
+        updates.push((src, dst));
+    }
+
+    for (src, dst) in updates {
+        let fetch_remote_name = src
+            .as_ref()
+            .and_then(|x| git.config.get_string(&format!("branch.{}.remote", x)).ok())
+            .filter(|x| x != ".")
+            .unwrap_or_else(|| "origin".to_string());
+        let remote_name = match args.remote_name.as_ref() {
+            Some(remote_name) => remote_name.clone(),
+            None => src
+                .as_ref()
+                .and_then(|x| get_push_remote(&git, x).0)
+                .unwrap_or_else(|| fetch_remote_name.clone()),
+        };
+
+        if args.force || src.is_none() {
+            if let Some(rule) = common::get_protected_rule(&git.repo, &dst)? {
+                return Err(format!(
+                    "Refusing to {}: branch {} is protected by the rule `{}` \
+                    (git-tools.protected).",
+                    if src.is_none() {
+                        "delete"
+                    } else {
+                        "force push"
+                    },
+                    dst,
+                    rule
+                )
+                .into());
+            }
+        }
+
+        if args.no_verify_tools {
+            continue;
+        }
+
+        match src {
+            Some(src) => verify(&git, &remote_name, &fetch_remote_name, &src, &dst),
+            None => check_default_branch(&git, &remote_name, &dst),
+        }
+        .map_err(|err| format!("Branch {} rejected: {}", dst, err))?;
+    }
+
+    let mut command = Command::new("git");
+    command.arg("push");
+    if let (Some(name), None, None) = (
+        git.branch_name.as_ref(),
+        git.upstream.as_ref(),
+        args.remote_name.as_ref(),
+    ) {
+        command.args(["--set-upstream", "origin", name]);
+    }
+    command.args(args.options);
+    if args.force {
+        command.arg("--force-if-includes");
+        if !args.lease {
+            command.arg("--force-with-lease");
+        }
+    }
+    command.args(args.remote_name).args(args.refspecs);
+
+    Ok(command)
+}
+
+/// The options of git push that take a value in the next argument.
+const VALUE_OPTIONS: &[&str] = &["-o", "--push-option", "--repo", "--receive-pack", "--exec"];
+
+/// Arguments forwarded to git push.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ForwardedArgs {
+    /// Options of git push except the ones forcing the push.
+    options: Vec<String>,
+    remote_name: Option<String>,
+    refspecs: Vec<String>,
+    /// Force push (-f, --force, --force-with-lease or --force-if-includes).
+    force: bool,
+    /// A lease has been given (--force-with-lease).
+    lease: bool,
+    /// Delete the remote branches (-d, --delete).
+    delete: bool,
+    /// Skip the safety checks (--no-verify-tools, not forwarded).
+    no_verify_tools: bool,
+}
+
+impl ForwardedArgs {
+    /// Parse the arguments of git push, refusing the ones that would bypass the checks of
+    /// git-push2.
+    fn parse(args: Vec<String>) -> Result<ForwardedArgs, String> {
+        let mut forwarded = ForwardedArgs::default();
+        let mut positionals = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-f" | "--force" | "--force-if-includes" => forwarded.force = true,
+                "-d" | "--delete" => {
+                    forwarded.delete = true;
+                    forwarded.options.push(arg);
+                }
+                "--no-verify-tools" => forwarded.no_verify_tools = true,
+                "--all-mine" | "--create-remote" => {
+                    return Err(format!(
+                        "{} cannot be used with the arguments forwarded to git push.",
+                        arg
+                    ))
+                }
+                "--all" | "--branches" | "--mirror" | "--prune" => {
+                    return Err(format!(
+                        "{} is not supported by git push2, use git push instead.",
+                        arg
+                    ))
+                }
+                "--" => positionals.extend(args.by_ref()),
+                x if VALUE_OPTIONS.contains(&x) => {
+                    forwarded.options.push(arg);
+                    forwarded.options.extend(args.next());
+                }
+                x if x.starts_with("--force-with-lease") => {
+                    forwarded.force = true;
+                    forwarded.lease = true;
+                    forwarded.options.push(arg);
+                }
+                x if x.starts_with("--") => forwarded.options.push(arg),
+                x if x.starts_with("-o") => forwarded.options.push(arg),
+                x if x.starts_with('-') && x.len() > 2 => {
+                    return Err(format!(
+                        "{}: short options must be given separately to be forwarded to git push.",
+                        arg
+                    ))
+                }
+                x if x.starts_with('-') => forwarded.options.push(arg),
+                _ => positionals.push(arg),
+            }
+        }
+
+        if let Some(refspec) = positionals.iter().skip(1).find(|x| x.starts_with('+')) {
+            return Err(format!(
+                "{}: forced refspecs are not supported, use --force instead.",
+                refspec
+            ));
+        }
+
+        let mut positionals = positionals.into_iter();
+        forwarded.remote_name = positionals.next();
+        forwarded.refspecs = positionals.collect();
+
+        Ok(forwarded)
+    }
+}
+
+struct BranchPush {
+    branch_name: String,
+    fetch_remote_name: String,
+    push_remote_configured: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    UpToDate,
+    Created,
+    FastForwarded,
+    Forced,
+    Rejected(String),
+}
+
+/// Push the branches to the remote in one go. Returns the outcome of every branch given.
+fn push_branches(
+    git: &Git,
+    params: &Params,
+    remote_name: &str,
+    branches: &[BranchPush],
+) -> Result<Vec<Outcome>, Box<dyn std::error::Error>> {
+    let mut outcomes = Vec::new();
+    let mut leases = Vec::new();
+
+    for branch in branches {
+        let branch_name = branch.branch_name.as_str();
+
+        // NOTE: the branch is always pushed to a remote branch of the same name, even if the
+        //       upstream is another branch (e.g. the base branch)
+        let local_ref = format!("refs/heads/{}", branch_name);
+        let remote_ref = local_ref.clone();
+        let local_oid = git.repo.refname_to_id(&local_ref)?;
+        let remote_oid = match git
+            .repo
+            .refname_to_id(&format!("refs/remotes/{}/{}", remote_name, branch_name))
+        {
+            Ok(oid) => Some(oid),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let outcome = match remote_oid {
+            None => Outcome::Created,
+            Some(oid) if oid == local_oid => Outcome::UpToDate,
+            Some(oid) if git.repo.graph_descendant_of(local_oid, oid)? => Outcome::FastForwarded,
+            Some(_) if !params.force => Outcome::Rejected(
+                "the remote branch has commits that are not in your branch (non-fast-forward)."
+                    .to_string(),
+            ),
+            Some(oid) if !git.reflog_includes(branch_name, oid)? => Outcome::Rejected(format!(
+                "refusing to force push: {}/{} has commits that have never been integrated in \
+                your branch.",
+                remote_name, branch_name
+            )),
+            Some(_) => Outcome::Forced,
+        };
+
+        let outcome = match outcome {
+            Outcome::Forced => match common::get_protected_rule(&git.repo, branch_name)? {
+                Some(rule) => Outcome::Rejected(format!(
+                    "refusing to force push: branch {} is protected by the rule `{}` \
+                    (git-tools.protected).",
+                    branch_name, rule
+                )),
+                None => outcome,
+            },
+            _ => outcome,
+        };
+
+        let outcome = match outcome {
+            Outcome::Created | Outcome::FastForwarded | Outcome::Forced
+                if !params.no_verify_tools =>
+            {
+                match verify(
+                    git,
+                    remote_name,
+                    &branch.fetch_remote_name,
+                    branch_name,
+                    branch_name,
+                ) {
+                    Ok(()) => outcome,
+                    Err(err) => Outcome::Rejected(err.to_string()),
+                }
+            }
+            _ => outcome,
+        };
+
+        if outcome == Outcome::Forced {
+            leases.push((remote_ref, remote_oid.unwrap()));
+        }
+        outcomes.push(outcome);
+    }
+
+    let leases = leases
+        .iter()
+        .map(|(refname, oid)| (refname.as_str(), *oid))
+        .collect::<Vec<_>>();
+
+    // NOTE: the leases are checked before pushing so a branch updated on the remote since the
+    //       last fetch doesn't prevent the other branches from being pushed
+    for refname in common::get_stale_leases(&git.repo, remote_name, &leases)? {
+        if let Some(i) = branches
+            .iter()
+            .position(|x| refname == format!("refs/heads/{}", x.branch_name))
+        {
+            outcomes[i] = Outcome::Rejected(format!(
+                "stale info: {}/{} has been updated on the remote since the last fetch.",
+                remote_name, branches[i].branch_name
+            ));
+        }
+    }
+
+    let mut refspecs = Vec::new();
+    for (branch, outcome) in branches.iter().zip(outcomes.iter()) {
+        let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch.branch_name);
+
+        match outcome {
+            Outcome::Created | Outcome::FastForwarded => refspecs.push(refspec),
+            Outcome::Forced => refspecs.push(format!("+{}", refspec)),
+            _ => {}
+        }
+    }
+
+    if !refspecs.is_empty() {
+        let refspecs = refspecs.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+        for (refname, status) in
+            common::push_with_lease(&git.repo, remote_name, &refspecs, &leases)?
+        {
+            if let Some(message) = status {
+                let rejected = branches
+                    .iter()
+                    .position(|x| refname == format!("refs/heads/{}", x.branch_name));
+
+                if let Some(i) = rejected {
+                    outcomes[i] = Outcome::Rejected(message);
+                }
+            }
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// All the local branches that have commits that are not in the default branch.
+fn get_my_branches(git: &Git) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut branch_names = Vec::new();
+
+    for branch in git.repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let branch_name = branch.name()?.expect("valid UTF-8").to_string();
+        let default_branch = git.get_default_branch("origin")?;
+
+        if default_branch.strip_prefix("origin/") == Some(branch_name.as_str()) {
+            continue;
+        }
+
+        if git.repo.revparse_single(&default_branch).is_err()
+            || !git
+                .rev_list(
+                    &default_branch,
+                    &format!("refs/heads/{}", branch_name),
+                    false,
+                )?
+                .is_empty()
+        {
+            branch_names.push(branch_name);
+        }
+    }
+
+    Ok(branch_names)
+}
+
+/// Find the remote to push to: branch.<name>.pushRemote, remote.pushDefault or
+/// git-tools.push-remote. Also returns true if the push remote is already set for this branch
+/// by git's configuration.
+fn get_push_remote(git: &Git, branch_name: &str) -> (Option<String>, bool) {
+    if let Ok(remote_name) = git
+        .config
+        .get_string(&format!("branch.{}.pushRemote", branch_name))
+    {
+        return (Some(remote_name), true);
+    }
+
+    if let Ok(remote_name) = git.config.get_string("remote.pushDefault") {
+        return (Some(remote_name), true);
+    }
+
+    (git.config.get_string("git-tools.push-remote").ok(), false)
+}
+
+fn create_push_remote(
+    git: &Git,
+    remote_name: &str,
+    fetch_remote_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let template = git
+        .config
+        .get_string("git-tools.push-remote-url")
+        .map_err(|_| "Cannot create the push remote: git-tools.push-remote-url is not set.")?;
+
+    let fetch_remote = git.repo.find_remote(fetch_remote_name)?;
+    let repo = fetch_remote
+        .url()
+        .and_then(|url| {
+            url.trim_end_matches('/')
+                .trim_end_matches(".git")
+                .rsplit(['/', ':'])
+                .next()
+        })
+        .ok_or("Cannot find the name of the repository.")?;
+    let url = template.replace("{repo}", repo);
+
+    git.repo.remote(remote_name, &url)?;
+    println!("Remote {} created: {}", remote_name, url);
+
+    Ok(())
+}
+
+const DEFAULT_MAX_FILE_SIZE: i64 = 10 * 1024 * 1024;
+
+/// Make sure the remote branch is not the default branch of the remote.
+fn check_default_branch(
+    git: &Git,
+    remote_name: &str,
+    remote_branch_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let push_default_branch = git.get_default_branch(remote_name)?;
+
+    if git
+        .config
+        .get_bool("push2.check-default-branch")
+        .unwrap_or(true)
+        && push_default_branch == format!("{}/{}", remote_name, remote_branch_name)
+    {
+        return Err(format!(
+            "pushing directly to the default branch {} (use --no-verify-tools to bypass).",
+            push_default_branch
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Safety checks before pushing the branch to the remote branch given.
+fn verify(
+    git: &Git,
+    remote_name: &str,
+    fetch_remote_name: &str,
+    branch_name: &str,
+    remote_branch_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_default_branch(git, remote_name, remote_branch_name)?;
+
+    // NOTE: the commits are compared to the default branch of the remote we fetch from
+    let default_branch = git.get_default_branch(fetch_remote_name)?;
+    let branch_ref = format!("refs/heads/{}", branch_name);
+
+    // NOTE: the other checks are made against the default branch which may not exist yet
+    let default_branch_oid = match git.repo.revparse_single(&default_branch) {
+        Ok(object) => object.peel_to_commit()?.id(),
+        Err(_) => return Ok(()),
+    };
+
+    if git
+        .config
+        .get_bool("push2.check-wip-commits")
+        .unwrap_or(true)
+    {
+        let mut wip_commits = Vec::new();
+        for revision in git.rev_list(&default_branch, &branch_ref, false)? {
+            let commit = git.repo.find_commit(git2::Oid::from_str(&revision)?)?;
+            let summary = commit.summary().unwrap_or_default();
+
+            if is_wip_commit(summary) {
+                wip_commits.push(format!("{} {}", &revision[..7], summary));
+            }
+        }
+
+        if !wip_commits.is_empty() {
+            return Err(format!(
+                "the branch contains commits that need to be squashed or finished (use \
+                --no-verify-tools to bypass):\n{}",
+                wip_commits.join("\n")
+            )
+            .into());
+        }
+    }
+
+    let max_file_size = git
+        .config
+        .get_i64("push2.max-file-size")
+        .unwrap_or(DEFAULT_MAX_FILE_SIZE);
+    if max_file_size > 0 {
+        let head = git.repo.find_reference(&branch_ref)?.peel_to_commit()?;
+        let merge_base = git.repo.merge_base(default_branch_oid, head.id())?;
+        let old_tree = git.repo.find_commit(merge_base)?.tree()?;
+        let diff = git
+            .repo
+            .diff_tree_to_tree(Some(&old_tree), Some(&head.tree()?), None)?;
+
+        let mut large_files = Vec::new();
+        for delta in diff.deltas() {
+            let file = delta.new_file();
+            let is_blob = matches!(
+                file.mode(),
+                git2::FileMode::Blob | git2::FileMode::BlobExecutable
+            );
+            if file.id().is_zero() || !is_blob {
+                continue;
+            }
+
+            let size = git.repo.find_blob(file.id())?.size() as i64;
+            if size > max_file_size {
+                large_files.push(format!(
+                    "{} ({} bytes)",
+                    file.path().unwrap_or_else(|| Path::new("?")).display(),
+                    size
+                ));
+            }
+        }
+
+        if !large_files.is_empty() {
+            return Err(format!(
+                "the branch introduces files larger than {} bytes (use --no-verify-tools to \
+                bypass):\n{}",
+                max_file_size,
+                large_files.join("\n")
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+fn is_wip_commit(summary: &str) -> bool {
+    let first_word = summary
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .next()
+        .unwrap_or_default();
+
+    summary.starts_with("fixup!")
+        || summary.starts_with("squash!")
+        || summary.starts_with("amend!")
+        || first_word.eq_ignore_ascii_case("wip")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wip_commits() {
+        assert!(is_wip_commit("WIP"));
+        assert!(is_wip_commit("wip: refactor the parser"));
+        assert!(is_wip_commit("Wip add tests"));
+        assert!(is_wip_commit("fixup! Add the parser"));
+        assert!(is_wip_commit("squash! Add the parser"));
+        assert!(is_wip_commit("amend! Add the parser"));
+    }
+
+    #[test]
+    fn regular_commits() {
+        assert!(!is_wip_commit("Add the parser"));
+        assert!(!is_wip_commit("Wipe the cache on logout"));
+        assert!(!is_wip_commit("Remove WIP marker from the docs"));
+        assert!(!is_wip_commit("Fixup the parser"));
+        assert!(!is_wip_commit(""));
+    }
+
+    fn parse(args: &[&str]) -> Result<ForwardedArgs, String> {
+        ForwardedArgs::parse(args.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn forwarded_args() {
+        let args = parse(&["-f", "-o", "ci.skip", "origin", "feat", "HEAD:main"]).unwrap();
+        assert_eq!(args.options, vec!["-o", "ci.skip"]);
+        assert_eq!(args.remote_name.as_deref(), Some("origin"));
+        assert_eq!(args.refspecs, vec!["feat", "HEAD:main"]);
+        assert!(args.force && !args.lease);
+
+        let args = parse(&["--force-with-lease=feat:abc123", "--tags", "--", "origin"]).unwrap();
+        assert_eq!(
+            args.options,
+            vec!["--force-with-lease=feat:abc123", "--tags"]
+        );
+        assert_eq!(args.remote_name.as_deref(), Some("origin"));
+        assert!(args.force && args.lease);
+
+        let args = parse(&["--delete", "--no-verify-tools", "origin", "old"]).unwrap();
+        assert_eq!(args.options, vec!["--delete"]);
+        assert_eq!(args.refspecs, vec!["old"]);
+        assert!(args.delete && args.no_verify_tools && !args.force);
+    }
+
+    #[test]
+    fn refused_forwarded_args() {
+        assert!(parse(&["--all-mine", "--tags"]).is_err());
+        assert!(parse(&["--create-remote", "-o", "ci.skip"]).is_err());
+        assert!(parse(&["--mirror"]).is_err());
+        assert!(parse(&["-fu"]).is_err());
+        assert!(parse(&["origin", "+feat"]).is_err());
+    }
 }
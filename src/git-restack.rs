@@ -0,0 +1,249 @@
+mod common;
+
+use common::{BranchType, Git};
+
+use globset::GlobSet;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    bin_name = "git restack",
+    about = env!("CARGO_PKG_DESCRIPTION")
+)]
+pub struct Restack {
+    /// Continue updating the stack after a merge conflict has been resolved and committed.
+    #[structopt(long = "continue", conflicts_with = "abort")]
+    resume: bool,
+
+    /// Forget about the stack update in progress.
+    #[structopt(long)]
+    abort: bool,
+
+    /// A branch of the stack to update (the current branch by default).
+    #[structopt(conflicts_with_all = &["resume", "abort"])]
+    branch_name: Option<String>,
+}
+
+fn main() {
+    let exit_status = execute();
+    std::io::stdout().flush().unwrap();
+    std::process::exit(exit_status);
+}
+
+const SUCCESS: i32 = 0;
+const FAILURE: i32 = 1;
+
+fn execute() -> i32 {
+    let opts = Restack::from_args();
+
+    if let Err(err) = run(opts) {
+        eprintln!("{}", err);
+
+        FAILURE
+    } else {
+        SUCCESS
+    }
+}
+
+pub fn run(params: Restack) -> Result<(), Box<dyn std::error::Error>> {
+    let mut git = Git::open()?;
+    let state_path = git.repo.path().join("git-tools-restack");
+
+    if params.abort {
+        fs::remove_file(&state_path)
+            .map_err(|_| "There is no stack update in progress, nothing to abort.")?;
+        println!("Stack update aborted.");
+
+        return Ok(());
+    }
+
+    if git.repo.state() != git2::RepositoryState::Clean {
+        return Err("A merge is in progress, please commit it first.".into());
+    }
+
+    if git.has_file_changes()? {
+        return Err("The repository has not committed changes, aborting.".into());
+    }
+
+    let (original_branch, mut queue) = if params.resume {
+        read_state(&state_path)?
+    } else if state_path.exists() {
+        return Err("A stack update is already in progress. \
+            Use --continue to resume it or --abort to forget about it."
+            .into());
+    } else {
+        let branch_name = match params.branch_name.clone() {
+            Some(branch_name) => branch_name,
+            None => git
+                .branch_name
+                .clone()
+                .ok_or("Cannot find the stack: HEAD is detached.")?,
+        };
+        let queue = get_stack(&git, &branch_name)?;
+
+        (git.branch_name.clone(), queue)
+    };
+
+    if queue.is_empty() {
+        println!("There is no stacked branch to update.");
+        return Ok(());
+    }
+
+    let ignore_conflict_set = git.get_ignore_conflict_globs()?;
+
+    while let Some(branch_name) = queue.front().cloned() {
+        let parent = git
+            .get_parent_branch(&branch_name)
+            .ok_or_else(|| format!("Branch {} has no parent anymore.", branch_name))?;
+
+        git.switch_branch(&branch_name)?;
+
+        if let Some(revision) = update_branch(&mut git, &parent, &ignore_conflict_set)? {
+            write_state(&state_path, original_branch.as_deref(), &queue)?;
+
+            println!("First merge conflict detected on: {}", revision);
+
+            let message = format!("Merge commit {} (conflicts)\n\n", revision);
+            Command::new("git")
+                .args([
+                    "merge",
+                    "--no-ff",
+                    revision.as_str(),
+                    "-m",
+                    message.as_str(),
+                ])
+                .status()?;
+
+            return Err(format!(
+                "Could not update {} without conflict. \
+                Once the conflict is resolved and committed, run: git restack --continue",
+                branch_name
+            )
+            .into());
+        }
+
+        queue.pop_front();
+    }
+
+    if state_path.exists() {
+        fs::remove_file(&state_path)?;
+    }
+
+    if let Some(branch_name) = original_branch {
+        git.switch_branch(&branch_name)?;
+    }
+
+    println!("The stack is up-to-date.");
+
+    Ok(())
+}
+
+/// Find all the branches of the stack of the branch given, in the order they need to be updated.
+/// The root of the stack is not included as it has no parent to be updated from.
+fn get_stack(git: &Git, branch_name: &str) -> Result<VecDeque<String>, Box<dyn std::error::Error>> {
+    let mut children: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for branch in git.repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = branch.name()?.expect("valid UTF-8").to_string();
+
+        if let Some(parent) = git.get_parent_branch(&name) {
+            children.entry(parent).or_default().push(name);
+        }
+    }
+
+    let mut root = branch_name.to_string();
+    let mut seen = HashSet::new();
+    while let Some(parent) = git.get_parent_branch(&root) {
+        if !seen.insert(root.clone()) {
+            return Err(format!("Branch {} is its own ancestor in the stack.", root).into());
+        }
+        if git.repo.find_branch(&parent, BranchType::Local).is_err() {
+            break;
+        }
+        root = parent;
+    }
+
+    let mut stack = VecDeque::new();
+    let mut pending = VecDeque::from(vec![root]);
+    while let Some(parent) = pending.pop_front() {
+        for child in children.remove(&parent).unwrap_or_default() {
+            pending.push_back(child.clone());
+            stack.push_back(child);
+        }
+    }
+
+    Ok(stack)
+}
+
+/// Merge as much as possible of the parent into the current branch. Returns the first revision
+/// that could not be merged without conflict if any.
+fn update_branch(
+    git: &mut Git,
+    parent: &str,
+    ignore_conflict_set: &GlobSet,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let branch_name = git.branch_name.clone().unwrap_or_default();
+    let mut rev_list = git.rev_list("HEAD", parent, true)?;
+
+    if rev_list.is_empty() {
+        println!(
+            "Branch {} is already up-to-date with {}.",
+            branch_name, parent
+        );
+        return Ok(None);
+    }
+
+    let mut last_failing_revision = None;
+    while let Some(revision) = rev_list.pop() {
+        let message = format!("Merge commit {} (no conflict)\n\n", revision);
+
+        if git
+            .merge_no_conflict(revision.as_str(), message.as_str(), ignore_conflict_set)?
+            .is_some()
+        {
+            break;
+        } else {
+            last_failing_revision = Some(revision);
+        }
+    }
+
+    if last_failing_revision.is_none() {
+        println!("Branch {} updated with {}.", branch_name, parent);
+    }
+
+    Ok(last_failing_revision)
+}
+
+fn read_state(
+    path: &Path,
+) -> Result<(Option<String>, VecDeque<String>), Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path).map_err(|_| "There is no stack update in progress.")?;
+    let mut lines = content.lines();
+    let original_branch = lines
+        .next()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string());
+
+    Ok((original_branch, lines.map(|x| x.to_string()).collect()))
+}
+
+fn write_state(
+    path: &Path,
+    original_branch: Option<&str>,
+    queue: &VecDeque<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut content = format!("{}\n", original_branch.unwrap_or_default());
+    for branch_name in queue {
+        content.push_str(branch_name);
+        content.push('\n');
+    }
+
+    fs::write(path, content)?;
+
+    Ok(())
+}
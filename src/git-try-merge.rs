@@ -2,7 +2,6 @@ mod common;
 
 use common::Git;
 
-use globset::{Glob, GlobSetBuilder};
 use std::collections::HashSet;
 use std::io::Write;
 use std::os::unix::process::CommandExt;
@@ -68,7 +67,7 @@ pub fn run(params: TryMerge) -> Result<(), Box<dyn std::error::Error>> {
 
 fn update_branch(mut git: Git, params: TryMerge) -> Result<(), Box<dyn std::error::Error>> {
     let default_branch = git.get_default_branch("origin")?;
-    let top_rev = params.revision.clone().unwrap_or_else(|| default_branch);
+    let top_rev = params.revision.clone().unwrap_or(default_branch);
 
     if top_rev.contains('/') {
         git.update_upstream(top_rev.as_str())?;
@@ -93,14 +92,7 @@ fn update_branch(mut git: Git, params: TryMerge) -> Result<(), Box<dyn std::erro
         return Ok(());
     }
 
-    let ignore_conflict_set = {
-        let mut builder = GlobSetBuilder::new();
-        let mut entries = git.config.multivar("try-merge.ignore-conflict", None)?;
-        while let Some(entry) = entries.next().transpose()? {
-            builder.add(Glob::new(entry.value().expect("invalid UTF-8"))?);
-        }
-        builder.build()?
-    };
+    let ignore_conflict_set = git.get_ignore_conflict_globs()?;
 
     let mut skipped = 0;
     let mut last_failing_revision: Option<String> = None;
@@ -143,7 +135,7 @@ fn update_branch(mut git: Git, params: TryMerge) -> Result<(), Box<dyn std::erro
         let message = format!("Merge commit {} (conflicts)\n\n", revision,);
 
         return Err(Command::new("git")
-            .args(&[
+            .args([
                 "merge",
                 "--no-ff",
                 revision.as_str(),